@@ -1,7 +1,10 @@
 use crate::calculator_capnp::calculator;
+use crate::negotiate;
+use crate::tls::{self, ClientStream, MaybeTlsStream};
 use capnp::capability::Promise;
 use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
 use futures::{AsyncReadExt, FutureExt};
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Copy)]
 pub struct PowerFunction;
@@ -26,8 +29,9 @@ impl calculator::function::Server for PowerFunction {
 
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = ::std::env::args().collect();
-    if args.len() != 3 {
-        println!("usage: {} client HOST:PORT", args[0]);
+    if args.len() < 3 || args.len() > 4 {
+        println!("usage: {} client HOST:PORT [CA_CERT_PATH]", args[0]);
+        println!("       (use a tls://HOST:PORT address to connect over TLS)");
         return Ok(());
     }
     tokio::task::LocalSet::new().run_until(try_main(args)).await
@@ -36,12 +40,45 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 async fn try_main(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
     use std::net::ToSocketAddrs;
 
-    let addr = args[2]
+    let (host_port, use_tls) = tls::strip_tls_scheme(&args[2]);
+    let addr = host_port
         .to_socket_addrs()?
         .next()
         .expect("could not parse address");
     let stream = tokio::net::TcpStream::connect(&addr).await?;
     stream.set_nodelay(true)?;
+
+    let mut stream: ClientStream = if use_tls {
+        let ca_path = PathBuf::from(args.get(3).map(String::as_str).unwrap_or("ca.pem"));
+        let connector = tls::client_connector(&ca_path)?;
+        let host = host_port
+            .rsplit_once(':')
+            .map(|(host, _port)| host)
+            .unwrap_or(host_port);
+        let server_name = rustls::ServerName::try_from(host)
+            .map_err(|e| format!("invalid server name {:?}: {}", host, e))?;
+        MaybeTlsStream::Tls(connector.connect(server_name, stream).await?)
+    } else {
+        MaybeTlsStream::Plain(stream)
+    };
+
+    let protocol_version = negotiate::negotiate(&mut stream).await?;
+    println!("negotiated protocol version: {}", protocol_version);
+    // This demo talks to a server built from the same tree, so it should
+    // offer the same `SUPPORTED_VERSIONS` and the two sides should land on
+    // the newest one in common. Unlike a membership check against the list
+    // `negotiate()` already draws from (which could never fail), this
+    // depends on the actual peer: an older or misconfigured server that
+    // only offers `calc/1.0` would negotiate down and trip this assertion,
+    // which is exactly the case the extended-feature demo blocks below
+    // need ruled out before they run.
+    assert_eq!(
+        protocol_version,
+        *negotiate::SUPPORTED_VERSIONS.last().unwrap(),
+        "expected to negotiate the newest shared protocol version, got {}",
+        protocol_version
+    );
+
     let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
 
     let network = Box::new(twoparty::VatNetwork::new(
@@ -308,5 +345,182 @@ async fn try_main(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
         println!("PASS");
     }
 
+    {
+        // 2 ^ 10, 17 % 5, sqrt(81), sin(0), cos(0), log(e), abs(-9), -(-9)
+
+        println!("Using the extended operators... ");
+
+        async fn eval_unary(
+            calculator: &calculator::Client,
+            op: calculator::Operator,
+            x: f64,
+        ) -> Result<f64, capnp::Error> {
+            let func = {
+                let mut request = calculator.get_operator_request();
+                request.get().set_op(op);
+                request.send().pipeline.get_func()
+            };
+            let mut request = calculator.evaluate_request();
+            {
+                let mut call = request.get().init_expression().init_call();
+                call.set_function(func);
+                call.init_params(1).get(0).set_literal(x);
+            }
+            let value = request.send().pipeline.get_value();
+            Ok(value.read_request().send().promise.await?.get()?.get_value())
+        }
+
+        async fn eval_binary(
+            calculator: &calculator::Client,
+            op: calculator::Operator,
+            x: f64,
+            y: f64,
+        ) -> Result<f64, capnp::Error> {
+            let func = {
+                let mut request = calculator.get_operator_request();
+                request.get().set_op(op);
+                request.send().pipeline.get_func()
+            };
+            let mut request = calculator.evaluate_request();
+            {
+                let mut call = request.get().init_expression().init_call();
+                call.set_function(func);
+                let mut params = call.init_params(2);
+                params.reborrow().get(0).set_literal(x);
+                params.get(1).set_literal(y);
+            }
+            let value = request.send().pipeline.get_value();
+            Ok(value.read_request().send().promise.await?.get()?.get_value())
+        }
+
+        assert_eq!(
+            eval_binary(&calculator, calculator::Operator::Power, 2.0, 10.0).await?,
+            1024.0
+        );
+        assert_eq!(
+            eval_binary(&calculator, calculator::Operator::Modulo, 17.0, 5.0).await?,
+            2.0
+        );
+        assert_eq!(
+            eval_unary(&calculator, calculator::Operator::Sqrt, 81.0).await?,
+            9.0
+        );
+        assert_eq!(eval_unary(&calculator, calculator::Operator::Sin, 0.0).await?, 0.0);
+        assert_eq!(eval_unary(&calculator, calculator::Operator::Cos, 0.0).await?, 1.0);
+        assert_eq!(
+            eval_unary(&calculator, calculator::Operator::Log, std::f64::consts::E).await?,
+            1.0
+        );
+        assert_eq!(eval_unary(&calculator, calculator::Operator::Abs, -9.0).await?, 9.0);
+        assert_eq!(
+            eval_unary(&calculator, calculator::Operator::Negate, -9.0).await?,
+            9.0
+        );
+
+        println!("PASS");
+    }
+
+    {
+        // Whatever functions the server loaded from its config file's
+        // `[functions]` table (possibly none) should already show up in
+        // listFunctions() with sane metadata, before we define anything
+        // of our own.
+
+        println!("Checking config-loaded function metadata... ");
+
+        let response = calculator
+            .list_functions_request()
+            .send()
+            .promise
+            .await?;
+        for entry in response.get()?.get_entries()? {
+            assert!(entry.get_param_count() <= 64);
+            assert!(entry.get_size_words() > 0);
+            assert!(entry.get_created_at_unix_secs() > 0);
+        }
+
+        println!("PASS");
+    }
+
+    {
+        // square(x) = x * x, stored under the name "square".
+        // defining it again should fail; getFunction and listFunctions
+        // should both be able to find it afterward.
+
+        println!("Defining and looking up a named function... ");
+
+        let before = calculator
+            .list_functions_request()
+            .send()
+            .promise
+            .await?
+            .get()?
+            .get_entries()?
+            .len();
+
+        {
+            let mut request = calculator.def_named_function_request();
+            {
+                let mut def_request = request.get();
+                def_request.set_name("square");
+                def_request.set_param_count(1);
+                let mut multiply_call = def_request.init_body().init_call();
+                multiply_call.set_function({
+                    let mut request = calculator.get_operator_request();
+                    request.get().set_op(calculator::Operator::Multiply);
+                    request.send().pipeline.get_func()
+                });
+                let mut params = multiply_call.init_params(2);
+                params.reborrow().get(0).set_parameter(0);
+                params.get(1).set_parameter(0);
+            }
+            request.send().promise.await?;
+        }
+
+        {
+            let mut request = calculator.def_named_function_request();
+            let mut def_request = request.get();
+            def_request.set_name("square");
+            def_request.set_param_count(1);
+            def_request.init_body().set_literal(0.0);
+            assert!(request.send().promise.await.is_err());
+        }
+
+        let square = {
+            let mut request = calculator.get_function_request();
+            request.get().set_name("square");
+            request.send().pipeline.get_func()
+        };
+        let mut eval_request = calculator.evaluate_request();
+        {
+            let mut call = eval_request.get().init_expression().init_call();
+            call.set_function(square);
+            call.init_params(1).get(0).set_literal(7.0);
+        }
+        let value = eval_request.send().pipeline.get_value();
+        assert_eq!(
+            value.read_request().send().promise.await?.get()?.get_value(),
+            49.0
+        );
+
+        let entries_response = calculator
+            .list_functions_request()
+            .send()
+            .promise
+            .await?;
+        let entries = entries_response.get()?.get_entries()?;
+        assert_eq!(entries.len(), before + 1);
+        assert!(entries.iter().any(|entry| {
+            entry
+                .get_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n == "square")
+                .unwrap_or(false)
+                && entry.get_param_count() == 1
+        }));
+
+        println!("PASS");
+    }
+
     Ok(())
 }
@@ -0,0 +1,176 @@
+//! TOML config file support for the server: where to listen, whether to
+//! require TLS, and a `[functions]` table of named expressions that are
+//! parsed once at startup (and again on every edit, see [`spawn_watcher`],
+//! which merges rather than replaces so it doesn't clobber functions a
+//! client registered at runtime) into [`FunctionImpl`]s the
+//! `CalculatorImpl` can hand out by name.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use crate::calculator_capnp::calculator;
+use crate::server::{FunctionImpl, FunctionTable, OperatorImpl};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub listen_addr: String,
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    #[serde(default)]
+    pub tls: Option<TlsSection>,
+    #[serde(default)]
+    pub functions: HashMap<String, FunctionSpec>,
+}
+
+fn default_nodelay() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsSection {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FunctionSpec {
+    /// Names bound to `Expression::parameter` indices 0..params.len(), in
+    /// order, e.g. `params = ["x", "y"]` lets `expr` reference `x` and `y`.
+    pub params: Vec<String>,
+    pub expr: String,
+}
+
+impl Config {
+    pub async fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = tokio::fs::read_to_string(path).await?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// Parses every `[functions]` entry into a `FunctionImpl`, ready to be
+    /// installed as (or swapped into) a `CalculatorImpl`'s `FunctionTable`.
+    pub fn build_functions(&self) -> Result<HashMap<String, Rc<FunctionImpl>>, Box<dyn std::error::Error>> {
+        let mut table = HashMap::new();
+        for (name, spec) in &self.functions {
+            let ast = crate::expr::parse(&spec.expr, &spec.params)?;
+            let mut message = capnp::message::Builder::new_default();
+            crate::expr::build(&ast, message.init_root::<calculator::expression::Builder>());
+            let func = FunctionImpl::new(
+                spec.params.len() as u32,
+                message.get_root_as_reader::<calculator::expression::Reader>()?,
+            )?;
+            table.insert(name.clone(), Rc::new(func));
+        }
+        Ok(table)
+    }
+}
+
+/// Watches `path` for changes and, whenever it's rewritten, reparses it and
+/// merges the rebuilt functions into `functions`. Runs as a task on the
+/// current `LocalSet`, since `FunctionTable` is `Rc`-based and not `Send`.
+///
+/// `functions` is shared with `defNamedFunction` (see `CalculatorImpl` in
+/// server.rs), which inserts client-registered functions into the very same
+/// map. A reload must only add, update, or remove the names *it* owns --
+/// those last seen in the config file -- tracked here as
+/// `known_config_names`; it must never touch names a client registered at
+/// runtime, or an unrelated config edit would silently delete them out from
+/// under any client that had stored functions by name.
+pub fn spawn_watcher(path: PathBuf, functions: FunctionTable, initial_config_names: HashSet<String>) {
+    use notify::{RecursiveMode, Watcher};
+
+    let (changed_tx, mut changed_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+
+    // `notify`'s watcher blocks the thread it polls on, so it gets its own
+    // OS thread; it only has to forward a "something changed" ping across
+    // to the LocalSet task below, which does the actual (Rc-based) work.
+    //
+    // We watch the *parent directory*, not the file itself: editors and
+    // config-management tools (vim, `sed -i`, Ansible templating, ...)
+    // typically save by writing a new file and renaming it over the old
+    // one, which replaces the inode a direct file watch would be holding.
+    // After such an edit the watch would go silently dead. Watching the
+    // directory and filtering by filename survives the rename.
+    let watch_path = path.clone();
+    let watch_dir = watch_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let watch_name = watch_path.file_name().map(|n| n.to_os_string());
+    std::thread::spawn(move || {
+        let (event_tx, event_rx) = std::sync::mpsc::channel();
+        let mut watcher = match notify::recommended_watcher(event_tx) {
+            Ok(w) => w,
+            Err(e) => {
+                println!("config watcher: failed to start: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = watcher.watch(&watch_dir, RecursiveMode::NonRecursive) {
+            println!("config watcher: failed to watch {:?}: {:?}", watch_dir, e);
+            return;
+        }
+        for event in event_rx {
+            let matches = match (&event, &watch_name) {
+                (Ok(event), Some(name)) => event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == Some(name.as_os_str())),
+                (Ok(_), None) => true,
+                (Err(_), _) => false,
+            };
+            if matches && changed_tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::task::spawn_local(async move {
+        let mut known_config_names = initial_config_names;
+        while changed_rx.recv().await.is_some() {
+            match Config::from_file(&path).await {
+                Ok(config) => match config.build_functions() {
+                    Ok(table) => {
+                        let mut functions = functions.borrow_mut();
+                        // A name a client already registered at runtime stays
+                        // the client's, even if the config now uses it too.
+                        let table: HashMap<String, Rc<FunctionImpl>> = table
+                            .into_iter()
+                            .filter(|(name, _)| {
+                                known_config_names.contains(name) || !functions.contains_key(name)
+                            })
+                            .collect();
+                        let new_config_names: HashSet<String> = table.keys().cloned().collect();
+
+                        let stale = known_config_names.difference(&new_config_names);
+                        let mut removed = 0;
+                        for name in stale {
+                            functions.remove(name);
+                            removed += 1;
+                        }
+                        let updated = table.len();
+                        functions.extend(table);
+
+                        println!(
+                            "config reloaded: {} function(s) updated, {} removed, {} runtime-registered function(s) left untouched",
+                            updated,
+                            removed,
+                            functions.len() - new_config_names.len(),
+                        );
+
+                        drop(functions);
+                        known_config_names = new_config_names;
+                    }
+                    Err(e) => println!("config reload: bad function table: {:?}", e),
+                },
+                Err(e) => println!("config reload: failed to read {:?}: {:?}", path, e),
+            }
+        }
+    });
+}
+
+pub(crate) fn operator_function(op: calculator::Operator) -> calculator::function::Client {
+    capnp_rpc::new_client(OperatorImpl::new(op))
+}
@@ -0,0 +1,224 @@
+//! A small arithmetic expression parser used to turn the `expr` strings in
+//! a server config's `[functions]` table into `calculator::Expression`
+//! trees. Supports `+ - * / %` and unary `-`, parentheses, numeric
+//! literals, and parameter names declared in the function's `params` list.
+//!
+//! This only needs to run once per config (re)load, so it favors a plain
+//! recursive-descent implementation over anything fancier.
+
+use crate::calculator_capnp::calculator;
+use crate::config;
+
+#[derive(Debug, Clone)]
+pub enum Ast {
+    Literal(f64),
+    Parameter(u32),
+    BinOp(calculator::Operator, Box<Ast>, Box<Ast>),
+    Negate(Box<Ast>),
+}
+
+pub fn parse(expr: &str, params: &[String]) -> Result<Ast, String> {
+    let tokens = tokenize(expr)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        params,
+    };
+    let ast = parser.parse_additive()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(format!("unexpected trailing input in expression {:?}", expr));
+    }
+    Ok(ast)
+}
+
+/// Writes `ast` into `builder`, an `Expression` union slot. Operators are
+/// represented directly as local `OperatorImpl` capabilities (the same
+/// type `getOperator` hands out over RPC) rather than round-tripping
+/// through the network, since the server is building this tree for itself.
+pub fn build(ast: &Ast, builder: calculator::expression::Builder) {
+    match ast {
+        Ast::Literal(v) => builder.set_literal(*v),
+        Ast::Parameter(p) => builder.set_parameter(*p),
+        Ast::Negate(inner) => {
+            let mut call = builder.init_call();
+            call.set_function(config::operator_function(calculator::Operator::Negate));
+            let mut params = call.init_params(1);
+            build(inner, params.reborrow().get(0));
+        }
+        Ast::BinOp(op, lhs, rhs) => {
+            let mut call = builder.init_call();
+            call.set_function(config::operator_function(*op));
+            let mut params = call.init_params(2);
+            build(lhs, params.reborrow().get(0));
+            build(rhs, params.get(1));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    LParen,
+    RParen,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = expr.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\n' | '\r' => i += 1,
+            '+' => {
+                tokens.push(Token::Plus);
+                i += 1;
+            }
+            '-' => {
+                tokens.push(Token::Minus);
+                i += 1;
+            }
+            '*' => {
+                tokens.push(Token::Star);
+                i += 1;
+            }
+            '/' => {
+                tokens.push(Token::Slash);
+                i += 1;
+            }
+            '%' => {
+                tokens.push(Token::Percent);
+                i += 1;
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let value = text
+                    .parse::<f64>()
+                    .map_err(|e| format!("bad number {:?}: {}", text, e))?;
+                tokens.push(Token::Number(value));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            other => return Err(format!("unexpected character {:?} in expression", other)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    params: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn parse_additive(&mut self) -> Result<Ast, String> {
+        let mut lhs = self.parse_multiplicative()?;
+        loop {
+            match self.peek() {
+                Some(Token::Plus) => {
+                    self.pos += 1;
+                    let rhs = self.parse_multiplicative()?;
+                    lhs = Ast::BinOp(calculator::Operator::Add, Box::new(lhs), Box::new(rhs));
+                }
+                Some(Token::Minus) => {
+                    self.pos += 1;
+                    let rhs = self.parse_multiplicative()?;
+                    lhs = Ast::BinOp(calculator::Operator::Subtract, Box::new(lhs), Box::new(rhs));
+                }
+                _ => break,
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Ast, String> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            match self.peek() {
+                Some(Token::Star) => {
+                    self.pos += 1;
+                    let rhs = self.parse_unary()?;
+                    lhs = Ast::BinOp(calculator::Operator::Multiply, Box::new(lhs), Box::new(rhs));
+                }
+                Some(Token::Slash) => {
+                    self.pos += 1;
+                    let rhs = self.parse_unary()?;
+                    lhs = Ast::BinOp(calculator::Operator::Divide, Box::new(lhs), Box::new(rhs));
+                }
+                Some(Token::Percent) => {
+                    self.pos += 1;
+                    let rhs = self.parse_unary()?;
+                    lhs = Ast::BinOp(calculator::Operator::Modulo, Box::new(lhs), Box::new(rhs));
+                }
+                _ => break,
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Ast, String> {
+        if let Some(Token::Minus) = self.peek() {
+            self.pos += 1;
+            return Ok(Ast::Negate(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Ast, String> {
+        match self.tokens.get(self.pos).cloned() {
+            Some(Token::Number(v)) => {
+                self.pos += 1;
+                Ok(Ast::Literal(v))
+            }
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                let index = self
+                    .params
+                    .iter()
+                    .position(|p| *p == name)
+                    .ok_or_else(|| format!("unknown parameter {:?}", name))?;
+                Ok(Ast::Parameter(index as u32))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let inner = self.parse_additive()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::RParen) => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err("expected closing ')'".to_string()),
+                }
+            }
+            other => Err(format!("unexpected token {:?}", other)),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! A tiny multistream-select-style handshake run on the raw stream before
+//! `twoparty::VatNetwork` takes it over, so peers agree on a protocol
+//! version before any capnp-rpc traffic (including bootstrap) flows.
+//!
+//! Wire format, all integers little-endian:
+//!   offer:     nonce:u64  count:u8  (len:u8 bytes:[u8; len])*count
+//!   selection: ok:u8      (len:u8 bytes:[u8; len])?        // ok == 0 means "na"
+//!
+//! Both sides always write an offer and read the peer's offer. Whichever
+//! nonce is larger acts as the selector: it picks the highest version both
+//! offered (or "na" if none overlap) and writes the selection; the other
+//! side just reads it. Comparing nonces this way keeps the handshake
+//! correct even in a simultaneous-open where both ends think of themselves
+//! as the initiator.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Versions this build understands, oldest first. The last entry is
+/// preferred.
+pub const SUPPORTED_VERSIONS: &[&str] = &["calc/1.0", "calc/1.1"];
+
+pub async fn negotiate<S>(stream: &mut S) -> Result<String, Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let nonce: u64 = rand::random();
+
+        write_offer(stream, nonce, SUPPORTED_VERSIONS).await?;
+        let (peer_nonce, peer_versions) = read_offer(stream).await?;
+
+        if nonce == peer_nonce {
+            // An exact tie leaves neither side able to defer to the other
+            // (both would think they're the selector, or both would wait
+            // to read one). Redraw and retry the whole exchange instead of
+            // deadlocking; with 64 bits of nonce this resolves essentially
+            // immediately.
+            continue;
+        }
+
+        let best = SUPPORTED_VERSIONS
+            .iter()
+            .rev()
+            .find(|v| peer_versions.iter().any(|p| p == *v))
+            .map(|v| v.to_string());
+
+        return if nonce > peer_nonce {
+            write_selection(stream, best.as_deref()).await?;
+            best.ok_or_else(|| "no protocol version in common with peer".into())
+        } else {
+            read_selection(stream)
+                .await?
+                .ok_or_else(|| "peer reported no protocol version in common".into())
+        };
+    }
+}
+
+async fn write_offer<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    nonce: u64,
+    versions: &[&str],
+) -> std::io::Result<()> {
+    stream.write_all(&nonce.to_le_bytes()).await?;
+    stream.write_all(&[versions.len() as u8]).await?;
+    for v in versions {
+        stream.write_all(&[v.len() as u8]).await?;
+        stream.write_all(v.as_bytes()).await?;
+    }
+    stream.flush().await
+}
+
+async fn read_offer<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<(u64, Vec<String>)> {
+    let mut nonce_buf = [0u8; 8];
+    stream.read_exact(&mut nonce_buf).await?;
+    let nonce = u64::from_le_bytes(nonce_buf);
+
+    let mut count_buf = [0u8; 1];
+    stream.read_exact(&mut count_buf).await?;
+
+    let mut versions = Vec::with_capacity(count_buf[0] as usize);
+    for _ in 0..count_buf[0] {
+        let mut len_buf = [0u8; 1];
+        stream.read_exact(&mut len_buf).await?;
+        let mut text_buf = vec![0u8; len_buf[0] as usize];
+        stream.read_exact(&mut text_buf).await?;
+        versions.push(String::from_utf8_lossy(&text_buf).into_owned());
+    }
+    Ok((nonce, versions))
+}
+
+async fn write_selection<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    version: Option<&str>,
+) -> std::io::Result<()> {
+    match version {
+        Some(v) => {
+            stream.write_all(&[1u8]).await?;
+            stream.write_all(&[v.len() as u8]).await?;
+            stream.write_all(v.as_bytes()).await?;
+        }
+        None => {
+            stream.write_all(&[0u8]).await?;
+        }
+    }
+    stream.flush().await
+}
+
+async fn read_selection<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<Option<String>> {
+    let mut ok_buf = [0u8; 1];
+    stream.read_exact(&mut ok_buf).await?;
+    if ok_buf[0] == 0 {
+        return Ok(None);
+    }
+    let mut len_buf = [0u8; 1];
+    stream.read_exact(&mut len_buf).await?;
+    let mut text_buf = vec![0u8; len_buf[0] as usize];
+    stream.read_exact(&mut text_buf).await?;
+    Ok(Some(String::from_utf8_lossy(&text_buf).into_owned()))
+}
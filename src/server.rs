@@ -1,9 +1,22 @@
 use capnp::{capability::Promise, primitive_list, Error};
 use capnp_rpc::{rpc_twoparty_capnp, twoparty, ImbuedMessageBuilder, RpcSystem};
 use futures::{future, AsyncReadExt, FutureExt, TryFutureExt};
-use std::net::SocketAddr;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 use crate::calculator_capnp::calculator;
+use crate::config::Config;
+use crate::negotiate;
+use crate::tls::{self, MaybeTlsStream, ServerStream};
+
+/// Functions known to a `CalculatorImpl`, keyed by name. Populated from the
+/// `[functions]` table in the config file, extended by `defNamedFunction`,
+/// and updated in place by the config file watcher whenever the file
+/// changes (it merges the config's names and leaves runtime-registered
+/// ones alone).
+pub(crate) type FunctionTable = Rc<RefCell<HashMap<String, Rc<FunctionImpl>>>>;
 
 struct ValueImpl {
     value: f64,
@@ -63,24 +76,51 @@ fn evaluate_impl(
     }
 }
 
-struct FunctionImpl {
+pub(crate) struct FunctionImpl {
     param_count: u32,
     body: ImbuedMessageBuilder<::capnp::message::HeapAllocator>,
+    created_at: std::time::SystemTime,
 }
 impl FunctionImpl {
-    fn new(param_count: u32, body: calculator::expression::Reader) -> ::capnp::Result<Self> {
+    pub(crate) fn new(
+        param_count: u32,
+        body: calculator::expression::Reader,
+    ) -> ::capnp::Result<Self> {
         let mut result = Self {
             param_count,
             body: ImbuedMessageBuilder::new(::capnp::message::HeapAllocator::new()),
+            created_at: std::time::SystemTime::now(),
         };
         result.body.set_root(body)?;
 
         Ok(result)
     }
-}
-impl calculator::function::Server for FunctionImpl {
-    fn call(
-        &mut self,
+
+    pub(crate) fn param_count(&self) -> u32 {
+        self.param_count
+    }
+
+    pub(crate) fn created_at(&self) -> std::time::SystemTime {
+        self.created_at
+    }
+
+    /// Size, in words, of the function's serialized expression body --
+    /// part of the metadata `listFunctions()` reports for stored functions.
+    pub(crate) fn size_words(&self) -> ::capnp::Result<u64> {
+        Ok(self
+            .body
+            .get_root::<calculator::expression::Builder>()?
+            .into_reader()
+            .total_size()?
+            .word_count)
+    }
+
+    /// Shared implementation of `function::Server::call`, usable both from
+    /// an owned `FunctionImpl` (the anonymous capability `defFunction`
+    /// returns) and from an `Rc<FunctionImpl>` stored in a `FunctionTable`
+    /// and handed out to many callers.
+    fn call_impl(
+        &self,
         params: calculator::function::CallParams,
         mut results: calculator::function::CallResults,
     ) -> Promise<(), capnp::Error> {
@@ -103,11 +143,53 @@ impl calculator::function::Server for FunctionImpl {
         })
     }
 }
+impl calculator::function::Server for FunctionImpl {
+    fn call(
+        &mut self,
+        params: calculator::function::CallParams,
+        results: calculator::function::CallResults,
+    ) -> Promise<(), capnp::Error> {
+        self.call_impl(params, results)
+    }
+}
+impl calculator::function::Server for Rc<FunctionImpl> {
+    fn call(
+        &mut self,
+        params: calculator::function::CallParams,
+        results: calculator::function::CallResults,
+    ) -> Promise<(), capnp::Error> {
+        self.as_ref().call_impl(params, results)
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct OperatorImpl {
     op: calculator::Operator,
 }
+impl OperatorImpl {
+    pub(crate) fn new(op: calculator::Operator) -> Self {
+        Self { op }
+    }
+}
+
+/// Number of parameters each `Operator` expects: 2 for the binary
+/// arithmetic ops, 1 for the unary transcendental/sign ops.
+fn operator_arity(op: calculator::Operator) -> u32 {
+    match op {
+        calculator::Operator::Add
+        | calculator::Operator::Subtract
+        | calculator::Operator::Multiply
+        | calculator::Operator::Divide
+        | calculator::Operator::Power
+        | calculator::Operator::Modulo => 2,
+        calculator::Operator::Negate
+        | calculator::Operator::Sqrt
+        | calculator::Operator::Sin
+        | calculator::Operator::Cos
+        | calculator::Operator::Log
+        | calculator::Operator::Abs => 1,
+    }
+}
 
 impl calculator::function::Server for OperatorImpl {
     fn call(
@@ -116,22 +198,67 @@ impl calculator::function::Server for OperatorImpl {
         mut results: calculator::function::CallResults,
     ) -> Promise<(), capnp::Error> {
         let params = pry!(pry!(params.get()).get_params());
-        if params.len() != 2 {
-            Promise::err(Error::failed("Wrong number of parameters.".to_string()))
+        let expected = operator_arity(self.op);
+        if params.len() != expected {
+            return Promise::err(Error::failed(format!(
+                "Expected {} parameters but got {}.",
+                expected,
+                params.len()
+            )));
+        }
+        let v = match self.op {
+            calculator::Operator::Add => params.get(0) + params.get(1),
+            calculator::Operator::Subtract => params.get(0) - params.get(1),
+            calculator::Operator::Multiply => params.get(0) * params.get(1),
+            calculator::Operator::Divide => params.get(0) / params.get(1),
+            calculator::Operator::Power => params.get(0).powf(params.get(1)),
+            calculator::Operator::Modulo => params.get(0).rem_euclid(params.get(1)),
+            calculator::Operator::Negate => -params.get(0),
+            calculator::Operator::Sqrt => params.get(0).sqrt(),
+            calculator::Operator::Sin => params.get(0).sin(),
+            calculator::Operator::Cos => params.get(0).cos(),
+            calculator::Operator::Log => params.get(0).ln(),
+            calculator::Operator::Abs => params.get(0).abs(),
+        };
+        results.get().set_value(v);
+        Promise::ok(())
+    }
+}
+
+struct CalculatorImpl {
+    functions: FunctionTable,
+    /// Protocol version this connection negotiated before bootstrap; lets
+    /// methods added after `calc/1.0` reject peers too old to know about
+    /// them instead of silently misbehaving.
+    protocol_version: String,
+}
+impl CalculatorImpl {
+    fn new(functions: FunctionTable, protocol_version: String) -> Self {
+        Self {
+            functions,
+            protocol_version,
+        }
+    }
+
+    fn require_version(&self, feature: &str, min_version: &str) -> Result<(), capnp::Error> {
+        if parse_version(&self.protocol_version) < parse_version(min_version) {
+            Err(Error::failed(format!(
+                "{} requires protocol version {} or newer, but this connection negotiated {}",
+                feature, min_version, self.protocol_version
+            )))
         } else {
-            let v = match self.op {
-                calculator::Operator::Add => params.get(0) + params.get(1),
-                calculator::Operator::Subtract => params.get(0) - params.get(1),
-                calculator::Operator::Multiply => params.get(0) * params.get(1),
-                calculator::Operator::Divide => params.get(0) / params.get(1),
-            };
-            results.get().set_value(v);
-            Promise::ok(())
+            Ok(())
         }
     }
 }
 
-struct CalculatorImpl;
+/// Parses a `"calc/MAJOR.MINOR"` version string into a `(major, minor)`
+/// pair so versions compare numerically -- a plain string compare would
+/// put `"calc/1.10"` before `"calc/1.9"`.
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let (major, minor) = version.strip_prefix("calc/")?.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
 
 impl calculator::Server for CalculatorImpl {
     fn evaluate(
@@ -166,48 +293,188 @@ impl calculator::Server for CalculatorImpl {
         mut results: calculator::GetOperatorResults,
     ) -> Promise<(), capnp::Error> {
         let op = pry!(pry!(params.get()).get_op());
+        if !matches!(
+            op,
+            calculator::Operator::Add
+                | calculator::Operator::Subtract
+                | calculator::Operator::Multiply
+                | calculator::Operator::Divide
+        ) {
+            pry!(self.require_version("extended operators", "calc/1.1"));
+        }
         results
             .get()
-            .set_func(capnp_rpc::new_client(OperatorImpl { op }));
+            .set_func(capnp_rpc::new_client(OperatorImpl::new(op)));
+        Promise::ok(())
+    }
+    fn get_function(
+        &mut self,
+        params: calculator::GetFunctionParams,
+        mut results: calculator::GetFunctionResults,
+    ) -> Promise<(), capnp::Error> {
+        pry!(self.require_version("getFunction", "calc/1.1"));
+        let name = pry!(pry!(pry!(params.get()).get_name()).to_str());
+        match self.functions.borrow().get(name) {
+            Some(func) => {
+                results.get().set_func(capnp_rpc::new_client(func.clone()));
+                Promise::ok(())
+            }
+            None => Promise::err(Error::failed(format!("no such function: {}", name))),
+        }
+    }
+    fn def_named_function(
+        &mut self,
+        params: calculator::DefNamedFunctionParams,
+        _results: calculator::DefNamedFunctionResults,
+    ) -> Promise<(), capnp::Error> {
+        pry!(self.require_version("defNamedFunction", "calc/1.1"));
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        if self.functions.borrow().contains_key(&name) {
+            return Promise::err(Error::failed(format!(
+                "function {:?} is already defined",
+                name
+            )));
+        }
+        let func = pry!(FunctionImpl::new(
+            params.get_param_count(),
+            pry!(params.get_body())
+        ));
+        self.functions.borrow_mut().insert(name, Rc::new(func));
+        Promise::ok(())
+    }
+    fn list_functions(
+        &mut self,
+        _params: calculator::ListFunctionsParams,
+        mut results: calculator::ListFunctionsResults,
+    ) -> Promise<(), capnp::Error> {
+        pry!(self.require_version("listFunctions", "calc/1.1"));
+        let functions = self.functions.borrow();
+        let mut entries = results.get().init_entries(functions.len() as u32);
+        for (i, (name, func)) in functions.iter().enumerate() {
+            let mut entry = entries.reborrow().get(i as u32);
+            entry.set_name(name.as_str());
+            entry.set_param_count(func.param_count());
+            let created_at_unix_secs = func
+                .created_at()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            entry.set_created_at_unix_secs(created_at_unix_secs);
+            entry.set_size_words(pry!(func.size_words()));
+        }
         Promise::ok(())
     }
 }
 
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    use std::net::ToSocketAddrs;
     let args: Vec<String> = ::std::env::args().collect();
-    if args.len() != 3 {
-        println!("usage: {} server ADDRESS[:PORT]", args[0]);
+    if args.len() < 3 || args.len() > 5 {
+        println!("usage: {} server ADDRESS[:PORT] [CERT_PATH KEY_PATH]", args[0]);
+        println!("       {} server CONFIG.toml", args[0]);
+        println!("       (use a tls://ADDRESS[:PORT] address to require TLS)");
         return Ok(());
     }
 
-    let addr = args[2]
+    let (config, config_path) = if args.len() == 3 && args[2].ends_with(".toml") {
+        let path = PathBuf::from(&args[2]);
+        (Config::from_file(&path).await?, Some(path))
+    } else {
+        let (host_port, use_tls) = tls::strip_tls_scheme(&args[2]);
+        let tls = use_tls.then(|| crate::config::TlsSection {
+            cert_path: PathBuf::from(args.get(3).map(String::as_str).unwrap_or("server.crt")),
+            key_path: PathBuf::from(args.get(4).map(String::as_str).unwrap_or("server.key")),
+        });
+        (
+            Config {
+                listen_addr: host_port.to_string(),
+                nodelay: true,
+                tls,
+                functions: HashMap::new(),
+            },
+            None,
+        )
+    };
+
+    tokio::task::LocalSet::new()
+        .run_until(try_main(config, config_path))
+        .await
+}
+
+async fn try_main(
+    config: Config,
+    config_path: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::net::ToSocketAddrs;
+
+    let addr = config
+        .listen_addr
         .to_socket_addrs()?
         .next()
         .expect("could not parse address");
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
 
-    tokio::task::LocalSet::new().run_until(try_main(addr)).await
-}
+    let tls_acceptor = match &config.tls {
+        Some(tls_cfg) => Some(tls::server_acceptor(&tls_cfg.cert_path, &tls_cfg.key_path)?),
+        None => None,
+    };
 
-async fn try_main(addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    let calc: calculator::Client = capnp_rpc::new_client(CalculatorImpl);
+    let functions: FunctionTable = Rc::new(RefCell::new(config.build_functions()?));
+    if let Some(path) = config_path {
+        let initial_config_names = config.functions.keys().cloned().collect();
+        crate::config::spawn_watcher(path, functions.clone(), initial_config_names);
+    }
 
     loop {
         let (stream, _) = listener.accept().await?;
-        stream.set_nodelay(true)?;
-        let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
-        let rpc_network = Box::new(twoparty::VatNetwork::new(
-            reader,
-            writer,
-            rpc_twoparty_capnp::Side::Server,
-            Default::default(),
-        ));
-        let rpc_system = RpcSystem::new(rpc_network, Some(calc.clone().client));
-        tokio::task::spawn_local(Box::pin(
-            rpc_system
-                .map_err(|e| println!("error: {:?}", e))
-                .map(|_| ()),
-        ));
+        if config.nodelay {
+            stream.set_nodelay(true)?;
+        }
+
+        // Everything past `accept()` and `set_nodelay` -- the TLS handshake, the version
+        // negotiation, and the RPC system itself -- runs inside the
+        // spawned task so one slow or stuck client (or a plain client
+        // poking a tls:// port) can't block `accept()` from handing off
+        // the next connection, and can't take down the whole process.
+        let tls_acceptor = tls_acceptor.clone();
+        let functions = functions.clone();
+        tokio::task::spawn_local(Box::pin(async move {
+            let mut stream: ServerStream = match &tls_acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(s) => MaybeTlsStream::Tls(s),
+                    Err(e) => {
+                        println!("TLS handshake failed: {:?}", e);
+                        return;
+                    }
+                },
+                None => MaybeTlsStream::Plain(stream),
+            };
+
+            let protocol_version = match negotiate::negotiate(&mut stream).await {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("protocol negotiation failed: {:?}", e);
+                    return;
+                }
+            };
+
+            // Each connection gets its own `CalculatorImpl` so that the
+            // version it negotiated can gate the methods it's allowed to
+            // call, while still sharing the same underlying function
+            // registry.
+            let calc: calculator::Client =
+                capnp_rpc::new_client(CalculatorImpl::new(functions, protocol_version));
+
+            let (reader, writer) =
+                tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+            let rpc_network = Box::new(twoparty::VatNetwork::new(
+                reader,
+                writer,
+                rpc_twoparty_capnp::Side::Server,
+                Default::default(),
+            ));
+            let rpc_system = RpcSystem::new(rpc_network, Some(calc.client));
+            rpc_system.map_err(|e| println!("error: {:?}", e)).await;
+        }));
     }
 }
@@ -0,0 +1,123 @@
+//! TLS transport helpers shared by the client and server binaries.
+//!
+//! Both sides speak plain capnp-rpc over whatever stream they're handed, so
+//! all we need here is a way to turn a raw `TcpStream` into something that
+//! also implements `AsyncRead + AsyncWrite` once a handshake has happened,
+//! plus the boilerplate for loading certs/keys off disk.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream};
+
+/// Either a plaintext `TcpStream` or one wrapped in a completed TLS session,
+/// so the rest of the pipeline (`compat().split()` into `VatNetwork`) can
+/// stay oblivious to whether TLS was requested.
+pub enum MaybeTlsStream<T> {
+    Plain(TcpStream),
+    Tls(T),
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for MaybeTlsStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for MaybeTlsStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+pub type ServerStream = MaybeTlsStream<ServerTlsStream<TcpStream>>;
+pub type ClientStream = MaybeTlsStream<ClientTlsStream<TcpStream>>;
+
+/// Address scheme recognized on the command line: `tls://HOST:PORT` opts
+/// into encryption, anything else is treated as a plain `HOST:PORT`.
+pub fn strip_tls_scheme(addr: &str) -> (&str, bool) {
+    match addr.strip_prefix("tls://") {
+        Some(rest) => (rest, true),
+        None => (addr, false),
+    }
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<rustls::Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect())
+}
+
+fn load_private_key(path: &Path) -> io::Result<rustls::PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let keys = rustls_pemfile::pkcs8_private_keys(&mut reader)?;
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))?;
+    Ok(rustls::PrivateKey(key))
+}
+
+/// Builds the server-side TLS acceptor from a cert chain + private key on disk.
+pub fn server_acceptor(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<tokio_rustls::TlsAcceptor, Box<dyn std::error::Error>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds the client-side TLS connector, trusting exactly the CA root (or
+/// pinned leaf cert) found at `ca_path`.
+pub fn client_connector(ca_path: &Path) -> Result<tokio_rustls::TlsConnector, Box<dyn std::error::Error>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(&cert)?;
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+}